@@ -1,16 +1,757 @@
 // Copyright 2017 Pants project contributors (see CONTRIBUTORS.md).
 // Licensed under the Apache License, Version 2.0 (see LICENSE).
 
+use std::any::Any;
+use std::env;
+use std::ffi::CString;
+use std::io;
+use std::marker::PhantomData;
+use std::mem;
+use std::os::unix::ffi::OsStrExt;
+use std::os::unix::process::CommandExt;
+use std::panic;
 use std::path::PathBuf;
-use std::sync::{Arc, RwLock, RwLockReadGuard};
+use std::process;
+use std::ptr;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex};
+use std::thread;
+use std::time::Duration;
 
-use futures_cpupool::{self, CpuPool};
+use arc_swap::ArcSwap;
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use futures::sync::oneshot;
+use futures::{Async, Future, IntoFuture, Poll};
+use uuid::Uuid;
 
-use fs::{PosixFS, Snapshots};
+use fs::{Dir, File, Link, PathStat, PosixFS, Snapshot, Snapshots, Stat};
 use graph::{EntryId, Graph};
 use tasks::Tasks;
 use types::Types;
 
+type BoxFuture<T, E> = Box<::futures::Future<Item = T, Error = E> + Send>;
+
+/**
+ * The filesystem operations that the engine needs in order to walk a build graph: stat-ing
+ * paths, listing directory contents, resolving symlinks, and reading file contents, plus
+ * deciding which paths should be treated as ignored.
+ *
+ * `PosixFS` is the default (and, outside of tests, the only) implementation, but putting these methods
+ * behind a trait lets `Core` be constructed over other backends -- an in-memory Vfs for
+ * tests, a read-only overlay, or a virtualized/remote source tree -- without any Node having
+ * to know which one it is talking to.
+ */
+pub trait Vfs: Send + Sync {
+  fn stat(&self, path: PathBuf) -> BoxFuture<Stat, io::Error>;
+
+  fn read_link(&self, link: Link) -> BoxFuture<PathBuf, io::Error>;
+
+  fn scandir(&self, dir: Dir) -> BoxFuture<Vec<Stat>, io::Error>;
+
+  fn read_file(&self, file: File) -> BoxFuture<Vec<u8>, io::Error>;
+
+  fn is_ignored(&self, stat: &PathStat) -> bool;
+
+  /**
+   * Reinitializes any process-specific state (eg, an ignore-pattern cache keyed by pid) after
+   * a fork.
+   */
+  fn post_fork(&self);
+}
+
+impl Vfs for PosixFS {
+  fn stat(&self, path: PathBuf) -> BoxFuture<Stat, io::Error> {
+    PosixFS::stat(self, path)
+  }
+
+  fn read_link(&self, link: Link) -> BoxFuture<PathBuf, io::Error> {
+    PosixFS::read_link(self, link)
+  }
+
+  fn scandir(&self, dir: Dir) -> BoxFuture<Vec<Stat>, io::Error> {
+    PosixFS::scandir(self, dir)
+  }
+
+  fn read_file(&self, file: File) -> BoxFuture<Vec<u8>, io::Error> {
+    PosixFS::read_file(self, file)
+  }
+
+  fn is_ignored(&self, stat: &PathStat) -> bool {
+    PosixFS::is_ignored(self, stat)
+  }
+
+  fn post_fork(&self) {
+    PosixFS::post_fork(self)
+  }
+}
+
+
+/**
+ * Configuration for the pool that Node execution is submitted to.
+ *
+ * `max_in_flight` bounds the number of tasks that may be queued or running on the pool at
+ * once: a graph with a very large number of ready Nodes could otherwise submit all of them
+ * at once and exhaust memory before the pool has a chance to work through the backlog.
+ */
+#[derive(Clone, Copy, Debug)]
+pub struct PoolConfig {
+  pub pool_size: usize,
+  // Stack size (in bytes) given to each worker thread. Deeply recursive Node evaluation
+  // can overflow the platform default stack, so this needs to be adjustable.
+  pub stack_size_bytes: usize,
+  pub max_in_flight: usize,
+}
+
+impl Default for PoolConfig {
+  fn default() -> PoolConfig {
+    PoolConfig {
+      pool_size: 8,
+      stack_size_bytes: 4 * 1024 * 1024,
+      max_in_flight: 4096,
+    }
+  }
+}
+
+/**
+ * Coarse scheduling priority for work submitted to the `Executor`. A worker that has a
+ * choice always prefers `Foreground` work (eg, the Node currently being awaited by a
+ * client) over `Background` work (speculative or read-ahead Nodes), so that latency on
+ * the hot path isn't hidden behind a backlog of work nobody is waiting on yet.
+ */
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Priority {
+  Foreground,
+  Background,
+}
+
+type Job = Box<FnMut() + Send>;
+
+/**
+ * The outcome sent back over a `spawn_fn` job's `oneshot` channel: either the submitted
+ * closure's own `Result`, or the payload of a panic that the job caught rather than letting
+ * unwind through the worker thread that ran it (see `Executor::spawn_fn`).
+ */
+enum JobOutcome<T, E> {
+  Completed(Result<T, E>),
+  Panicked(Box<Any + Send>),
+}
+
+/**
+ * A future returned by `Executor::spawn_fn`, fulfilled by a worker thread via a
+ * `oneshot` channel once the submitted closure completes.
+ */
+pub struct PoolFuture<T, E> {
+  receiver: oneshot::Receiver<JobOutcome<T, E>>,
+}
+
+impl<T, E> Future for PoolFuture<T, E> {
+  type Item = T;
+  type Error = E;
+
+  fn poll(&mut self) -> Poll<T, E> {
+    match self.receiver.poll() {
+      Ok(Async::Ready(JobOutcome::Completed(Ok(item)))) => Ok(Async::Ready(item)),
+      Ok(Async::Ready(JobOutcome::Completed(Err(e)))) => Err(e),
+      Ok(Async::Ready(JobOutcome::Panicked(payload))) => panic::resume_unwind(payload),
+      Ok(Async::NotReady) => Ok(Async::NotReady),
+      Err(_) => panic!("A submitted task was dropped by the Executor before completing."),
+    }
+  }
+}
+
+/**
+ * A thread pool that Node execution is submitted to, replacing the flat FIFO
+ * `futures_cpupool::CpuPool` that used to live here. Each worker owns a local deque per
+ * priority; when those are empty it takes work from the shared injector of each priority
+ * (`Foreground` first) and then tries to steal from its siblings' deques. Every submission
+ * (via `spawn_fn` or `Scope::spawn`, from a worker thread or any other) is pushed onto a
+ * shared injector and nothing pushes onto a worker's local deque, so all work is taken from
+ * the injectors and the siblings' deques never hold anything to steal.
+ *
+ * `spawn_fn` applies the backpressure described on `PoolConfig`: once `max_in_flight` tasks
+ * are outstanding, further submitters block until a slot frees up, rather than letting the
+ * injector grow without bound. Jobs spawned via a `Scope` are not counted as outstanding and
+ * never wait for a slot.
+ */
+pub struct Executor {
+  foreground_injector: Arc<Injector<Job>>,
+  background_injector: Arc<Injector<Job>>,
+  stealers: Arc<Vec<(Stealer<Job>, Stealer<Job>)>>,
+  shutdown: Arc<AtomicBool>,
+  // Signaled whenever a job is pushed (or shutdown begins), so idle workers can block
+  // instead of burning a core apiece spinning on empty deques.
+  work_available: Arc<Condvar>,
+  work_available_lock: Arc<Mutex<()>>,
+  threads: Vec<thread::JoinHandle<()>>,
+  max_in_flight: usize,
+  outstanding: Arc<AtomicUsize>,
+  slot_freed: Arc<Condvar>,
+  slot_freed_lock: Mutex<()>,
+}
+
+impl Executor {
+  fn new(config: PoolConfig) -> Executor {
+    let foreground_injector = Arc::new(Injector::new());
+    let background_injector = Arc::new(Injector::new());
+    let shutdown = Arc::new(AtomicBool::new(false));
+    let work_available = Arc::new(Condvar::new());
+    let work_available_lock = Arc::new(Mutex::new(()));
+
+    let workers: Vec<(Worker<Job>, Worker<Job>)> = (0..config.pool_size)
+      .map(|_| (Worker::new_fifo(), Worker::new_fifo()))
+      .collect();
+    let stealers = Arc::new(
+      workers
+        .iter()
+        .map(|&(ref fg, ref bg)| (fg.stealer(), bg.stealer()))
+        .collect::<Vec<_>>(),
+    );
+
+    let threads = workers
+      .into_iter()
+      .enumerate()
+      .map(|(id, (fg_worker, bg_worker))| {
+        let foreground_injector = foreground_injector.clone();
+        let background_injector = background_injector.clone();
+        let stealers = stealers.clone();
+        let shutdown = shutdown.clone();
+        let work_available = work_available.clone();
+        let work_available_lock = work_available_lock.clone();
+        thread::Builder::new()
+          .name(format!("engine-{}", id))
+          .stack_size(config.stack_size_bytes)
+          .spawn(move || {
+            Executor::work_loop(
+              id,
+              fg_worker,
+              bg_worker,
+              &foreground_injector,
+              &background_injector,
+              &stealers,
+              &shutdown,
+              &work_available,
+              &work_available_lock,
+            )
+          })
+          .unwrap()
+      })
+      .collect();
+
+    Executor {
+      foreground_injector: foreground_injector,
+      background_injector: background_injector,
+      stealers: stealers,
+      shutdown: shutdown,
+      work_available: work_available,
+      work_available_lock: work_available_lock,
+      threads: threads,
+      max_in_flight: config.max_in_flight,
+      outstanding: Arc::new(AtomicUsize::new(0)),
+      slot_freed: Arc::new(Condvar::new()),
+      slot_freed_lock: Mutex::new(()),
+    }
+  }
+
+  fn work_loop(
+    id: usize,
+    fg_worker: Worker<Job>,
+    bg_worker: Worker<Job>,
+    foreground_injector: &Injector<Job>,
+    background_injector: &Injector<Job>,
+    stealers: &[(Stealer<Job>, Stealer<Job>)],
+    shutdown: &AtomicBool,
+    work_available: &Condvar,
+    work_available_lock: &Mutex<()>,
+  ) {
+    while !shutdown.load(Ordering::SeqCst) {
+      let task = fg_worker
+        .pop()
+        .or_else(|| foreground_injector.steal().success())
+        .or_else(|| bg_worker.pop())
+        .or_else(|| background_injector.steal().success())
+        .or_else(|| {
+          stealers
+            .iter()
+            .enumerate()
+            .filter(|&(idx, _)| idx != id)
+            .filter_map(|(_, &(ref fg, ref bg))| {
+              match fg.steal() {
+                Steal::Success(job) => Some(job),
+                _ => bg.steal().success(),
+              }
+            })
+            .next()
+        });
+
+      match task {
+        Some(mut job) => job(),
+        None => {
+          // Nothing to steal right now: park until a submitter pushes new work (or we're
+          // asked to shut down), rather than spinning a whole core waiting for one. The
+          // short timeout is a safety net against a missed wakeup racing a sibling's steal.
+          let guard = work_available_lock.lock().unwrap();
+          if !shutdown.load(Ordering::SeqCst) {
+            let _ = work_available.wait_timeout(guard, Duration::from_millis(50)).unwrap();
+          }
+        }
+      }
+    }
+  }
+
+  /**
+   * The number of tasks submitted via `spawn_fn` that are currently queued or running on the
+   * executor (jobs spawned via a `Scope` are not counted).
+   */
+  fn outstanding(&self) -> usize {
+    self.outstanding.load(Ordering::SeqCst)
+  }
+
+  /**
+   * Blocks the calling thread until fewer than `max_in_flight` tasks are outstanding, and
+   * then submits `f` at the given `Priority`. The outstanding count is decremented (and any
+   * submitter blocked on a free slot is woken) when the task completes.
+   */
+  fn spawn_fn<F, R>(&self, priority: Priority, f: F) -> PoolFuture<R::Item, R::Error>
+  where
+    F: FnOnce() -> R + Send + 'static,
+    R: IntoFuture + 'static,
+    R::Item: Send + 'static,
+    R::Error: Send + 'static,
+  {
+    {
+      let mut guard = self.slot_freed_lock.lock().unwrap();
+      while self.outstanding.load(Ordering::SeqCst) >= self.max_in_flight {
+        // Jobs notify `slot_freed` without holding `slot_freed_lock`, so a wakeup can land
+        // between the check above and this wait; the timeout bounds how long that stalls us.
+        guard = self
+          .slot_freed
+          .wait_timeout(guard, Duration::from_millis(50))
+          .unwrap()
+          .0;
+      }
+      self.outstanding.fetch_add(1, Ordering::SeqCst);
+    }
+
+    let outstanding = self.outstanding.clone();
+    let slot_freed = self.slot_freed.clone();
+    let (sender, receiver) = oneshot::channel();
+    let mut f = Some(f);
+    let job: Job = Box::new(move || {
+      let f = f.take().expect("A job must only run once.");
+      // Catches a panicking `f` here, inside the job, rather than letting it unwind out
+      // through `work_loop`: an unwind that reached there would kill the worker thread for
+      // good (nothing replaces it) and skip the `outstanding`/`slot_freed` bookkeeping
+      // below, permanently wedging one more `max_in_flight` slot every time it happened.
+      let outcome = match panic::catch_unwind(panic::AssertUnwindSafe(|| f().into_future().wait())) {
+        Ok(result) => JobOutcome::Completed(result),
+        Err(payload) => JobOutcome::Panicked(payload),
+      };
+      outstanding.fetch_sub(1, Ordering::SeqCst);
+      slot_freed.notify_one();
+      // The receiving end may already have been dropped if nobody polled the PoolFuture.
+      let _ = sender.send(outcome);
+    });
+
+    match priority {
+      Priority::Foreground => self.foreground_injector.push(job),
+      Priority::Background => self.background_injector.push(job),
+    }
+    // Wake any worker parked in `work_loop`'s idle wait so it picks this up promptly
+    // rather than waiting out the timeout.
+    self.work_available.notify_all();
+
+    PoolFuture { receiver: receiver }
+  }
+
+  /**
+   * Runs `f`, which is handed a `Scope` that it can use to spawn child work borrowing from
+   * the current stack frame. Blocks until every job spawned via that `Scope` has actually
+   * run before returning, so that none of those borrows can be used after they end.
+   *
+   * Unlike `crossbeam_utils::thread::scope`, child work here is pushed onto this Executor's
+   * own foreground queue and picked up by its existing workers (or stolen by them), rather
+   * than running on brand-new OS threads unrelated to the work-stealing pool.
+   */
+  pub fn scope<'a, F, R>(&self, f: F) -> R
+  where
+    F: FnOnce(&Scope<'a>) -> R,
+  {
+    let scope = Scope {
+      foreground_injector: self.foreground_injector.clone(),
+      work_available: self.work_available.clone(),
+      outstanding: Arc::new(AtomicUsize::new(0)),
+      done: Arc::new(Condvar::new()),
+      done_lock: Arc::new(Mutex::new(())),
+      panics: Arc::new(Mutex::new(Vec::new())),
+      _marker: PhantomData,
+    };
+
+    // A panic in `f` is held until the scoped jobs have finished, since they may still be
+    // borrowing from the frames it would unwind.
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| f(&scope)));
+
+    {
+      let mut guard = scope.done_lock.lock().unwrap();
+      while scope.outstanding.load(Ordering::SeqCst) != 0 {
+        guard = scope.done.wait(guard).unwrap();
+      }
+    }
+
+    let result = match result {
+      Ok(result) => result,
+      Err(payload) => panic::resume_unwind(payload),
+    };
+
+    if let Some(payload) = scope.panics.lock().unwrap().pop() {
+      // A scoped job panicked: the worker thread that ran it caught the unwind (see
+      // `Scope::spawn`) so the pool itself stays healthy, but `scope`'s caller still needs
+      // to find out -- re-raise it here, now that every scoped job has finished.
+      panic::resume_unwind(payload);
+    }
+
+    result
+  }
+}
+
+/**
+ * A scope created by `Executor::scope`, used to spawn work that borrows the enclosing stack
+ * frame for up to `'a`. See `Executor::scope`.
+ */
+pub struct Scope<'a> {
+  foreground_injector: Arc<Injector<Job>>,
+  work_available: Arc<Condvar>,
+  outstanding: Arc<AtomicUsize>,
+  done: Arc<Condvar>,
+  done_lock: Arc<Mutex<()>>,
+  panics: Arc<Mutex<Vec<Box<Any + Send>>>>,
+  _marker: PhantomData<&'a ()>,
+}
+
+impl<'a> Scope<'a> {
+  /**
+   * Spawns `f` onto the owning Executor's queues. `f` may borrow from the stack frame that
+   * created this `Scope`, because `Executor::scope` won't return (and so that frame won't
+   * be popped) until `f` -- and every other job spawned via this `Scope` -- has run.
+   */
+  pub fn spawn<F>(&self, f: F)
+  where
+    F: FnOnce() + Send + 'a,
+  {
+    self.outstanding.fetch_add(1, Ordering::SeqCst);
+    let outstanding = self.outstanding.clone();
+    let done = self.done.clone();
+    let done_lock = self.done_lock.clone();
+    let panics = self.panics.clone();
+
+    let mut f = Some(f);
+    let job: Box<FnMut() + Send + 'a> = Box::new(move || {
+      let f = f.take().expect("A scoped job must only run once.");
+      // As in `Executor::spawn_fn`, a panic here is caught rather than left to unwind
+      // through `work_loop` and kill the worker thread running it; `Executor::scope`
+      // re-raises it (on the thread that's waiting on the scope) once every scoped job
+      // spawned alongside this one has also finished.
+      if let Err(payload) = panic::catch_unwind(panic::AssertUnwindSafe(f)) {
+        panics.lock().unwrap().push(payload);
+      }
+      outstanding.fetch_sub(1, Ordering::SeqCst);
+      let _guard = done_lock.lock().unwrap();
+      done.notify_all();
+    });
+
+    // SAFETY: `job` borrows from the frame that created this `Scope` for up to `'a`, but
+    // the executor's queues only know how to hold `'static` jobs. This erases that bound,
+    // which is sound only because `Executor::scope` blocks until `outstanding` (incremented
+    // just above) returns to zero -- and therefore until this job has actually run and been
+    // dropped -- before the borrows it closed over could possibly become invalid.
+    let job: Job = unsafe { mem::transmute(job) };
+
+    self.foreground_injector.push(job);
+    self.work_available.notify_all();
+  }
+}
+
+impl Drop for Executor {
+  fn drop(&mut self) {
+    // NB: Deliberately does not join the worker threads. `post_fork` drops the pre-fork
+    // Executor (via `Core::pool`'s `ArcSwap::store`) to install a fresh one for the child
+    // process, but after a real `fork()` only the calling thread exists in the child -- the
+    // `JoinHandle`s here refer to threads that live in the parent, if anywhere. Joining them
+    // would be undefined behavior against dead/foreign thread handles and would hang or
+    // panic every freshly-forked child. Workers observe `shutdown` on their own and exit;
+    // letting their `JoinHandle`s simply drop detaches them, which is what
+    // `futures_cpupool::CpuPool` did here as well.
+    self.shutdown.store(true, Ordering::SeqCst);
+    self.work_available.notify_all();
+  }
+}
+
+/**
+ * A single build action to execute: an argv/env plus the Snapshot of inputs that the
+ * sandbox should be populated with before running it.
+ *
+ * `tool_roots` lists the absolute host paths (eg a hermetic interpreter distribution, or
+ * `/usr` itself) that `argv[0]` needs in order to exec and dynamically link at all. Each is
+ * bind-mounted read-only into the sandbox at the same path before the action runs; without
+ * this, the action would see nothing but its declared inputs and immediately fail to exec.
+ */
+pub struct ProcessRequest {
+  pub argv: Vec<String>,
+  pub env: Vec<(String, String)>,
+  pub input_snapshot: Snapshot,
+  pub tool_roots: Vec<PathBuf>,
+}
+
+/**
+ * The result of a sandboxed action: its exit code, captured stdout/stderr, and a Snapshot
+ * of its whole scratch directory as the action left it (the materialized inputs included).
+ */
+pub struct ProcessResult {
+  pub exit_code: i32,
+  pub stdout: Vec<u8>,
+  pub stderr: Vec<u8>,
+  pub output_snapshot: Snapshot,
+}
+
+/**
+ * Runs build actions hermetically: materializes the declared input `Snapshot` into a fresh
+ * per-action scratch directory, bind-mounts the request's declared `tool_roots` into that
+ * same directory read-only, and runs the action so that it can only see those declared
+ * inputs and tools rather than the host filesystem.
+ *
+ * On Linux this is done with an unprivileged mount namespace: a new user namespace maps
+ * the calling uid/gid so that `mount`/`pivot_root` are permitted without root, the scratch
+ * directory is bind-mounted onto itself to become a mount point, each `tool_root` is
+ * bind-mounted read-only underneath it at the same absolute path, and `pivot_root` swaps
+ * the whole thing in as `/` (falling back to `chroot` if `pivot_root` is unavailable, eg
+ * because the old root can't be unmounted). The old root is detached with `MNT_DETACH`
+ * once the pivot has happened, so nothing under it leaks into the action. On platforms
+ * without namespace support, this degrades to running the action as a plain subprocess
+ * rooted at the scratch directory -- not leak-proof, but still reproducible given
+ * identical inputs.
+ */
+pub struct Sandbox {
+  work_dir: PathBuf,
+}
+
+impl Sandbox {
+  fn new(work_dir: PathBuf) -> Sandbox {
+    Sandbox { work_dir: work_dir }
+  }
+
+  /**
+   * Runs `req` in a fresh per-action scratch directory under `work_dir`, and captures the
+   * resulting output tree back into a Snapshot. This is synchronous (materializing,
+   * spawning, and waiting on the action, and capturing its outputs, all block the calling
+   * thread) -- callers should submit it to the `Executor` via `spawn_fn` rather than
+   * calling it directly off of an async context. The scratch directory is removed once the
+   * action is done with it, whether or not it succeeded.
+   */
+  pub fn run(&self, snapshots: &Snapshots, req: ProcessRequest) -> Result<ProcessResult, String> {
+    self.with_scratch_dir(|sandbox_dir| Sandbox::run_in(sandbox_dir, snapshots, &req))
+  }
+
+  /**
+   * Hands `f` a fresh per-action scratch directory nested under `work_dir`, and removes it
+   * once `f` returns, regardless of whether it succeeded -- an action that already produced
+   * a result shouldn't fail because its scratch directory couldn't be torn down, but leaving
+   * it behind on every call is exactly how these accumulate and exhaust disk over a real
+   * build.
+   */
+  fn with_scratch_dir<F, T>(&self, f: F) -> T
+  where
+    F: FnOnce(&PathBuf) -> T,
+  {
+    let sandbox_dir = self.work_dir.join(Uuid::new_v4().to_string());
+    let result = f(&sandbox_dir);
+    let _ = ::std::fs::remove_dir_all(&sandbox_dir);
+    result
+  }
+
+  fn run_in(
+    sandbox_dir: &PathBuf,
+    snapshots: &Snapshots,
+    req: &ProcessRequest,
+  ) -> Result<ProcessResult, String> {
+    ::std::fs::create_dir_all(sandbox_dir)
+      .map_err(|e| format!("Failed to create sandbox dir {:?}: {:?}", sandbox_dir, e))?;
+    snapshots
+      .materialize(&req.input_snapshot, sandbox_dir)
+      .wait()
+      .map_err(|e| format!("Failed to materialize inputs into sandbox: {:?}", e))?;
+    let (exit_code, stdout, stderr) = Sandbox::spawn_in_root(sandbox_dir, req)?;
+    let output_snapshot = snapshots
+      .capture(sandbox_dir)
+      .wait()
+      .map_err(|e| format!("Failed to capture sandbox outputs: {:?}", e))?;
+    Ok(ProcessResult {
+      exit_code: exit_code,
+      stdout: stdout,
+      stderr: stderr,
+      output_snapshot: output_snapshot,
+    })
+  }
+
+  /**
+   * Builds the `Command` common to every platform: `argv[0]`, run with a clean environment
+   * overlaid with `env`, with its working directory set to `sandbox_dir`. Platform-specific
+   * `spawn_in_root` variants layer namespacing (or the lack of it) on top of this.
+   */
+  fn plain_command(sandbox_dir: &PathBuf, argv: &[String], env: &[(String, String)]) -> process::Command {
+    let mut command = process::Command::new(&argv[0]);
+    command
+      .args(&argv[1..])
+      .env_clear()
+      .envs(env.iter().cloned())
+      .current_dir(sandbox_dir);
+    command
+  }
+
+  #[cfg(target_os = "linux")]
+  fn spawn_in_root(sandbox_dir: &PathBuf, req: &ProcessRequest) -> Result<(i32, Vec<u8>, Vec<u8>), String> {
+    if req.argv.is_empty() {
+      return Err("ProcessRequest.argv must contain at least the binary to execute".to_owned());
+    }
+    // Isolating the action in its own mount (and user) namespace means that a `pivot_root`
+    // inside the child only affects that child: the engine process (and every other
+    // concurrently-running action) keeps seeing the host filesystem exactly as before.
+    let mut command = Sandbox::plain_command(sandbox_dir, &req.argv, &req.env);
+
+    unsafe {
+      let sandbox_dir = sandbox_dir.clone();
+      let tool_roots = req.tool_roots.clone();
+      command.pre_exec(move || Sandbox::enter_sandbox_namespace(&sandbox_dir, &tool_roots));
+    }
+
+    Sandbox::wait_for_output(command)
+  }
+
+  #[cfg(not(target_os = "linux"))]
+  fn spawn_in_root(sandbox_dir: &PathBuf, req: &ProcessRequest) -> Result<(i32, Vec<u8>, Vec<u8>), String> {
+    if req.argv.is_empty() {
+      return Err("ProcessRequest.argv must contain at least the binary to execute".to_owned());
+    }
+    // No namespace support on this platform: fall back to a plain subprocess rooted at the
+    // scratch dir. This is reproducible given identical inputs, but not leak-proof -- the
+    // action can still see the rest of the host filesystem.
+    let command = Sandbox::plain_command(sandbox_dir, &req.argv, &req.env);
+    Sandbox::wait_for_output(command)
+  }
+
+  /**
+   * Bind-mounts `tool_root` (eg `/usr`, or a hermetic interpreter distribution) read-only
+   * at the same absolute path underneath `sandbox_dir`, so that an action which execs a
+   * binary living under `tool_root` can still find it -- and its dynamic linker, shared
+   * libraries, and any other files it reads relative to that root -- once it is confined
+   * to the sandbox. `MS_BIND` mounts ignore `MS_RDONLY` on the initial call, so enforcing
+   * read-only takes a second `MS_REMOUNT` pass.
+   */
+  #[cfg(target_os = "linux")]
+  fn bind_mount_tool_root(sandbox_dir: &PathBuf, tool_root: &PathBuf) -> io::Result<()> {
+    let relative = tool_root.strip_prefix("/").unwrap_or(tool_root);
+    let target = sandbox_dir.join(relative);
+    ::std::fs::create_dir_all(&target)?;
+
+    let tool_root_c = CString::new(tool_root.as_os_str().as_bytes()).unwrap();
+    let target_c = CString::new(target.as_os_str().as_bytes()).unwrap();
+    let none: *const libc::c_char = ptr::null();
+
+    if unsafe {
+      libc::mount(tool_root_c.as_ptr(), target_c.as_ptr(), none, libc::MS_BIND, ptr::null())
+    } != 0
+    {
+      return Err(io::Error::last_os_error());
+    }
+    if unsafe {
+      libc::mount(
+        none,
+        target_c.as_ptr(),
+        none,
+        libc::MS_BIND | libc::MS_REMOUNT | libc::MS_RDONLY,
+        ptr::null(),
+      )
+    } != 0
+    {
+      return Err(io::Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  /**
+   * Creates a new user+mount namespace mapping the current uid/gid (so that the following
+   * mount operations are permitted without root), bind-mounts the sandbox dir in as the new
+   * root plus each of `tool_roots` read-only underneath it, and `pivot_root`s into it,
+   * detaching the old root so that nothing outside of the sandbox remains reachable. Falls
+   * back to `chroot` if `pivot_root` fails (eg because the current root can't be lazily
+   * unmounted).
+   */
+  #[cfg(target_os = "linux")]
+  fn enter_sandbox_namespace(sandbox_dir: &PathBuf, tool_roots: &[PathBuf]) -> io::Result<()> {
+    let uid = unsafe { libc::getuid() };
+    let gid = unsafe { libc::getgid() };
+
+    if unsafe { libc::unshare(libc::CLONE_NEWUSER | libc::CLONE_NEWNS) } != 0 {
+      return Err(io::Error::last_os_error());
+    }
+    ::std::fs::write("/proc/self/setgroups", b"deny")?;
+    ::std::fs::write("/proc/self/uid_map", format!("0 {} 1", uid).as_bytes())?;
+    ::std::fs::write("/proc/self/gid_map", format!("0 {} 1", gid).as_bytes())?;
+
+    let old_root = sandbox_dir.join(".old_root");
+    ::std::fs::create_dir_all(&old_root)?;
+
+    let sandbox_dir_c = CString::new(sandbox_dir.as_os_str().as_bytes()).unwrap();
+    let old_root_c = CString::new(old_root.as_os_str().as_bytes()).unwrap();
+    let none: *const libc::c_char = ptr::null();
+
+    // Bind-mount the sandbox onto itself so that it is a mount point, which `pivot_root`
+    // requires of its new root.
+    if unsafe {
+      libc::mount(
+        sandbox_dir_c.as_ptr(),
+        sandbox_dir_c.as_ptr(),
+        none,
+        libc::MS_BIND,
+        ptr::null(),
+      )
+    } != 0
+    {
+      return Err(io::Error::last_os_error());
+    }
+
+    for tool_root in tool_roots {
+      Sandbox::bind_mount_tool_root(sandbox_dir, tool_root)?;
+    }
+
+    let pivot_ok = unsafe { libc::syscall(libc::SYS_pivot_root, sandbox_dir_c.as_ptr(), old_root_c.as_ptr()) } == 0;
+    if pivot_ok {
+      env::set_current_dir("/")?;
+      let old_root_c = CString::new("/.old_root").unwrap();
+      if unsafe { libc::umount2(old_root_c.as_ptr(), libc::MNT_DETACH) } != 0 {
+        return Err(io::Error::last_os_error());
+      }
+    } else {
+      // pivot_root isn't always available (eg: the root filesystem can't be unmounted
+      // lazily in some container setups) -- chroot is weaker (it doesn't detach the old
+      // root), but still confines relative path resolution to the sandbox.
+      if unsafe { libc::chroot(sandbox_dir_c.as_ptr()) } != 0 {
+        return Err(io::Error::last_os_error());
+      }
+      env::set_current_dir("/")?;
+    }
+    Ok(())
+  }
+
+  fn wait_for_output(mut command: process::Command) -> Result<(i32, Vec<u8>, Vec<u8>), String> {
+    let output = command
+      .output()
+      .map_err(|e| format!("Failed to spawn sandboxed process: {:?}", e))?;
+    Ok((
+      output.status.code().unwrap_or(-1),
+      output.stdout,
+      output.stderr,
+    ))
+  }
+}
 
 /**
  * The core context shared (via Arc) between the Scheduler and the Context objects of
@@ -23,58 +764,93 @@ pub struct Core {
   pub tasks: Tasks,
   pub types: Types,
   pub snapshots: Snapshots,
-  pub vfs: PosixFS,
+  pub vfs: Arc<dyn Vfs>,
+  pub sandbox: Sandbox,
   // TODO: This is a second pool (relative to the VFS pool), upon which all work is
   // submitted. See https://github.com/pantsbuild/pants/issues/4298
-  pool: RwLock<CpuPool>,
+  //
+  // An `ArcSwap` rather than an `RwLock`: `pool()` is on the hot path of every Node's
+  // execution, and a `RwLock` read guard is a serialization point even though `post_fork` is
+  // the only writer and only ever runs between generations. Swapping an `Arc` lets readers
+  // load a fresh, owned handle without ever blocking on (or being blocked by) a writer.
+  pool: ArcSwap<Executor>,
+  pool_config: PoolConfig,
 }
 
 impl Core {
+  /**
+   * Constructs a Core backed by the default `PosixFS` Vfs implementation over `build_root`.
+   */
   pub fn new(
     tasks: Tasks,
     types: Types,
     build_root: PathBuf,
     ignore_patterns: Vec<String>,
     work_dir: PathBuf,
+    pool_config: PoolConfig,
+  ) -> Core {
+    // FIXME: Errors in initialization should definitely be exposed as python
+    // exceptions, rather than as panics.
+    let vfs =
+      PosixFS::new(build_root, ignore_patterns)
+      .unwrap_or_else(|e| {
+        panic!("Could not initialize VFS: {:?}", e);
+      });
+    Core::new_with_vfs(tasks, types, Arc::new(vfs), work_dir, pool_config)
+  }
+
+  /**
+   * Constructs a Core over an arbitrary `Vfs` implementation, for use with backends other
+   * than the default `PosixFS` (eg, an in-memory Vfs for tests).
+   */
+  pub fn new_with_vfs(
+    tasks: Tasks,
+    types: Types,
+    vfs: Arc<dyn Vfs>,
+    work_dir: PathBuf,
+    pool_config: PoolConfig,
   ) -> Core {
     Core {
       graph: Graph::new(),
       tasks: tasks,
       types: types,
-      snapshots: Snapshots::new(work_dir)
+      snapshots: Snapshots::new(work_dir.clone())
         .unwrap_or_else(|e| {
           panic!("Could not initialize Snapshot directory: {:?}", e);
         }),
-      // FIXME: Errors in initialization should definitely be exposed as python
-      // exceptions, rather than as panics.
-      vfs:
-        PosixFS::new(build_root, ignore_patterns)
-        .unwrap_or_else(|e| {
-          panic!("Could not initialize VFS: {:?}", e);
-        }),
-      pool: RwLock::new(Core::create_pool()),
+      vfs: vfs,
+      sandbox: Sandbox::new(work_dir.join("sandboxes")),
+      pool: ArcSwap::from(Arc::new(Executor::new(pool_config))),
+      pool_config: pool_config,
     }
   }
 
-  pub fn pool(&self) -> RwLockReadGuard<CpuPool> {
-    self.pool.read().unwrap()
+  /**
+   * A lock-free load of the current Executor. Returns an owned `Arc` handle rather than a
+   * guard, so holding onto the result across a `post_fork` swap is harmless: the caller just
+   * keeps using the (still valid) Executor it loaded.
+   */
+  pub fn pool(&self) -> Arc<Executor> {
+    self.pool.load_full()
   }
 
-  fn create_pool() -> CpuPool {
-    futures_cpupool::Builder::new()
-      .name_prefix("engine-")
-      .create()
+  /**
+   * The number of tasks submitted via `spawn_fn` that are currently queued or running on the
+   * work pool, for observability.
+   */
+  pub fn pool_outstanding(&self) -> usize {
+    self.pool.load().outstanding()
   }
 
   /**
-   * Reinitializes a Core in a new process (basically, recreates its CpuPool).
+   * Reinitializes a Core in a new process (basically, recreates its Executor).
    */
   pub fn post_fork(&self) {
     // Reinitialize the VFS pool.
     self.vfs.post_fork();
-    // And our own.
-    let mut pool = self.pool.write().unwrap();
-    *pool = Core::create_pool();
+    // And our own. In-flight readers that already loaded the old Executor keep it alive
+    // (and keep using it) via their own Arc handle; this swap only affects new loads.
+    self.pool.store(Arc::new(Executor::new(self.pool_config)));
   }
 }
 
@@ -91,11 +867,28 @@ impl Context {
       core: core,
     }
   }
+
+  /**
+   * Hermetically runs `req` via the Core's Sandbox, with the Core's Snapshot store as the
+   * source of inputs and destination for captured outputs.
+   *
+   * Submitted as `Background` work on the Core's `Executor` rather than run inline: this is
+   * the heaviest-weight work the engine does (an entire subprocess's lifetime plus
+   * materialize/capture IO), and running it inline would occupy whatever worker called
+   * `run_sandboxed` for that whole duration without it ever counting against the pool's
+   * `max_in_flight`, defeating the backpressure `PoolConfig` provides.
+   */
+  pub fn run_sandboxed(&self, req: ProcessRequest) -> PoolFuture<ProcessResult, String> {
+    let core = self.core.clone();
+    self.core.pool().spawn_fn(Priority::Background, move || {
+      core.sandbox.run(&core.snapshots, req)
+    })
+  }
 }
 
 pub trait ContextFactory {
   fn create(&self, entry_id: EntryId) -> Context;
-  fn pool(&self) -> RwLockReadGuard<CpuPool>;
+  fn pool(&self) -> Arc<Executor>;
 }
 
 impl ContextFactory for Context {
@@ -110,7 +903,199 @@ impl ContextFactory for Context {
     }
   }
 
-  fn pool(&self) -> RwLockReadGuard<CpuPool> {
+  fn pool(&self) -> Arc<Executor> {
     self.core.pool()
   }
 }
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::sync::Barrier;
+
+  /**
+   * A `Vfs` backed by nothing at all: every read fails, and nothing is ever ignored. Stands
+   * in for `PosixFS` in tests that care about `Core` being pluggable, not about the contents
+   * of any particular filesystem.
+   */
+  struct NoFilesVfs;
+
+  impl Vfs for NoFilesVfs {
+    fn stat(&self, _path: PathBuf) -> BoxFuture<Stat, io::Error> {
+      Box::new(::futures::future::err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "NoFilesVfs has no files",
+      )))
+    }
+
+    fn read_link(&self, _link: Link) -> BoxFuture<PathBuf, io::Error> {
+      Box::new(::futures::future::err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "NoFilesVfs has no files",
+      )))
+    }
+
+    fn scandir(&self, _dir: Dir) -> BoxFuture<Vec<Stat>, io::Error> {
+      Box::new(::futures::future::err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "NoFilesVfs has no files",
+      )))
+    }
+
+    fn read_file(&self, _file: File) -> BoxFuture<Vec<u8>, io::Error> {
+      Box::new(::futures::future::err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "NoFilesVfs has no files",
+      )))
+    }
+
+    fn is_ignored(&self, _stat: &PathStat) -> bool {
+      false
+    }
+
+    fn post_fork(&self) {}
+  }
+
+  #[test]
+  fn vfs_trait_is_usable_with_an_alternate_backend() {
+    let vfs: Arc<dyn Vfs> = Arc::new(NoFilesVfs);
+
+    assert!(
+      vfs
+        .read_file(File(PathBuf::from("some/file")))
+        .wait()
+        .is_err()
+    );
+    assert!(!vfs.is_ignored(&PathStat::File {
+      path: PathBuf::from("some/file"),
+      stat: File(PathBuf::from("some/file")),
+    }));
+  }
+
+  #[test]
+  fn executor_blocks_new_work_once_max_in_flight_is_reached() {
+    let executor = Arc::new(Executor::new(PoolConfig {
+      pool_size: 2,
+      stack_size_bytes: 1024 * 1024,
+      max_in_flight: 1,
+    }));
+
+    // Occupies the only in-flight slot until released, so that a second spawn_fn below has
+    // to block waiting for a free slot rather than racing ahead of it.
+    let release = Arc::new(Barrier::new(2));
+    let blocked_release = release.clone();
+    let blocked = executor.spawn_fn(Priority::Foreground, move || {
+      blocked_release.wait();
+      Ok::<(), ()>(())
+    });
+
+    assert_eq!(executor.outstanding(), 1);
+
+    // Submitted on another thread because spawn_fn blocks the calling thread until a slot is
+    // free, and that slot won't free up until `release.wait()` below lets the first job finish.
+    let second_executor = executor.clone();
+    let second = thread::spawn(move || {
+      second_executor
+        .spawn_fn(Priority::Foreground, || Ok::<(), ()>(()))
+        .wait()
+    });
+
+    release.wait();
+
+    assert_eq!(blocked.wait(), Ok(()));
+    assert_eq!(second.join().unwrap(), Ok(()));
+    assert_eq!(executor.outstanding(), 0);
+  }
+
+  #[test]
+  fn executor_survives_a_panicking_job() {
+    let executor = Executor::new(PoolConfig {
+      pool_size: 1,
+      stack_size_bytes: 1024 * 1024,
+      max_in_flight: 4,
+    });
+
+    let panicked = executor.spawn_fn(Priority::Foreground, || -> Result<(), ()> {
+      panic!("boom");
+    });
+    assert!(
+      panic::catch_unwind(panic::AssertUnwindSafe(|| panicked.wait())).is_err()
+    );
+
+    // The slot the panicking job occupied should have been freed rather than leaked, and
+    // its worker should still be alive to pick up more work rather than having died with it.
+    assert_eq!(executor.outstanding(), 0);
+    assert_eq!(
+      executor
+        .spawn_fn(Priority::Foreground, || Ok::<i32, ()>(42))
+        .wait(),
+      Ok(42)
+    );
+  }
+
+  #[test]
+  fn scope_waits_for_work_borrowing_the_caller_s_stack_frame() {
+    let executor = Executor::new(PoolConfig::default());
+    let counter = AtomicUsize::new(0);
+
+    executor.scope(|scope| {
+      for _ in 0..8 {
+        scope.spawn(|| {
+          counter.fetch_add(1, Ordering::SeqCst);
+        });
+      }
+    });
+
+    assert_eq!(counter.load(Ordering::SeqCst), 8);
+  }
+
+  #[test]
+  fn scope_reraises_a_scoped_panic_after_sibling_work_completes() {
+    let executor = Executor::new(PoolConfig::default());
+    let sibling_ran = AtomicUsize::new(0);
+
+    let result = panic::catch_unwind(panic::AssertUnwindSafe(|| {
+      executor.scope(|scope| {
+        scope.spawn(|| panic!("boom"));
+        scope.spawn(|| {
+          sibling_ran.fetch_add(1, Ordering::SeqCst);
+        });
+      });
+    }));
+
+    assert!(result.is_err());
+    assert_eq!(sibling_ran.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn plain_command_runs_rooted_at_the_sandbox_dir() {
+    let sandbox_dir = env::temp_dir().join(format!("context-test-plain-command-{}", Uuid::new_v4()));
+    ::std::fs::create_dir_all(&sandbox_dir).unwrap();
+
+    let mut command =
+      Sandbox::plain_command(&sandbox_dir, &["/bin/pwd".to_owned()], &[]);
+    let output = command.output().unwrap();
+
+    assert_eq!(
+      String::from_utf8_lossy(&output.stdout).trim(),
+      sandbox_dir.canonicalize().unwrap().to_str().unwrap(),
+    );
+
+    let _ = ::std::fs::remove_dir_all(&sandbox_dir);
+  }
+
+  #[test]
+  fn with_scratch_dir_cleans_up_even_when_the_closure_errors() {
+    let sandbox = Sandbox::new(env::temp_dir().join("context-test-sandbox-cleanup"));
+    let mut seen_dir = None;
+
+    let result: Result<(), String> = sandbox.with_scratch_dir(|sandbox_dir| {
+      ::std::fs::create_dir_all(sandbox_dir).unwrap();
+      seen_dir = Some(sandbox_dir.clone());
+      Err("boom".to_owned())
+    });
+
+    assert_eq!(result, Err("boom".to_owned()));
+    assert!(!seen_dir.unwrap().exists());
+  }
+}